@@ -1,14 +1,19 @@
 use anyhow::{Error, Result};
+use bzip2::write::{BzDecoder, BzEncoder};
 use env_logger::Env;
-use flate2::write::GzDecoder;
-use gzp::deflate::Gzip;
+use flate2::bufread::GzDecoder as BufGzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use gzp::deflate::{Bgzf, Gzip};
 use gzp::parz::Compression;
+use gzp::snap::Snap;
 use gzp::ZBuilder;
-use log::info;
+use log::{error, info};
+use rayon::prelude::*;
 use std::fs::File;
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
 use structopt::{clap::AppSettings::ColoredHelp, StructOpt};
 
 /// Get a bufferd input reader from stdin or a file
@@ -52,6 +57,40 @@ fn is_broken_pipe(err: &Error) -> bool {
     false
 }
 
+/// The compression formats crabz knows how to read and write.
+///
+/// `Gzip`, `Bgzf`, and `Snap` are compressed through gzp's multithreaded `ZBuilder`,
+/// while `Zstd` and `Bzip2` are backed by their respective crates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Gzip,
+    Bgzf,
+    Zstd,
+    Bzip2,
+    Snap,
+}
+
+impl Format {
+    fn possible_values() -> &'static [&'static str] {
+        &["gzip", "bgzf", "zstd", "bzip2", "snap"]
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" => Ok(Format::Gzip),
+            "bgzf" => Ok(Format::Bgzf),
+            "zstd" => Ok(Format::Zstd),
+            "bzip2" => Ok(Format::Bzip2),
+            "snap" => Ok(Format::Snap),
+            _ => Err(Error::msg(format!("Unknown format: {}", s))),
+        }
+    }
+}
+
 /// A small POC program to compress files like pigz.
 ///
 /// This will use all threads possible on your system.
@@ -62,9 +101,11 @@ struct Opts {
     #[structopt(short, long)]
     output: Option<PathBuf>,
 
-    /// Input file to read from, "-" to read from stdin
+    /// Input files to read from, "-" to read from stdin. If more than one is given, each is
+    /// written to a derived output path (e.g. `foo` -> `foo.gz`) and removed on success unless
+    /// `--keep` is set
     #[structopt(name = "FILE", parse(from_os_str))]
-    file: Option<PathBuf>,
+    file: Vec<PathBuf>,
 
     /// Compression level
     #[structopt(short, long, default_value = "3")]
@@ -77,6 +118,243 @@ struct Opts {
     /// Flag to switch to decompressing inputs. Note: this flag may change in future releases
     #[structopt(short, long)]
     decompress: bool,
+
+    /// Compression format to use. If not set, it is inferred from the input/output file
+    /// extensions, falling back to gzip when reading from stdin or when no extension is
+    /// recognized
+    #[structopt(long, possible_values = Format::possible_values())]
+    format: Option<Format>,
+
+    /// With `--format bgzf`, write a companion virtual-offset index alongside the output,
+    /// recording each block's compressed start offset and cumulative uncompressed offset
+    #[structopt(long)]
+    index: Option<PathBuf>,
+
+    /// Uncompressed byte range to decompress, e.g. "1000:2000". Requires `--format bgzf` and
+    /// an `--index` previously written for this file, and uses it to inflate only the blocks
+    /// covering the requested range instead of the whole file
+    #[structopt(long)]
+    region: Option<Region>,
+
+    /// Keep (don't remove) input files after successful compression/decompression
+    #[structopt(short, long)]
+    keep: bool,
+
+    /// Overwrite existing output files instead of refusing to run
+    #[structopt(short, long)]
+    force: bool,
+
+    /// Stop processing remaining files after the first error, instead of reporting it and
+    /// continuing with the rest
+    #[structopt(long)]
+    stop_on_error: bool,
+
+    /// Train a zstd dictionary from the sample files matched by this directory or glob pattern,
+    /// writing it to `--dict-out`, instead of compressing/decompressing anything
+    #[structopt(long)]
+    train: Option<String>,
+
+    /// Path to write the dictionary trained via `--train`
+    #[structopt(long, requires = "train")]
+    dict_out: Option<PathBuf>,
+
+    /// Target size in bytes for the dictionary trained via `--train`
+    #[structopt(long, default_value = "112640")]
+    dict_max_size: usize,
+
+    /// Prime zstd compression/decompression with a dictionary produced by `--train`. Small,
+    /// similar payloads (log lines, JSON records) compress dramatically better this way
+    #[structopt(long)]
+    dict: Option<PathBuf>,
+}
+
+/// Dictionary training needs more than one or two samples to find shared structure.
+const MIN_TRAINING_SAMPLES: usize = 5;
+
+/// Resolve `pattern` into concrete sample file paths: every file in a directory, or every match
+/// of a glob pattern.
+fn sample_paths(pattern: &str) -> Result<Vec<PathBuf>> {
+    let as_dir = PathBuf::from(pattern);
+    if as_dir.is_dir() {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&as_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        return Ok(paths);
+    }
+
+    let mut paths = Vec::new();
+    for entry in glob::glob(pattern)? {
+        let path = entry?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Train a zstd dictionary from the sample files matched by `pattern`, writing it to `dict_out`.
+fn run_train(pattern: &str, dict_out: &PathBuf, max_size: usize) -> Result<()> {
+    let paths = sample_paths(pattern)?;
+    if paths.len() < MIN_TRAINING_SAMPLES {
+        return Err(Error::msg(format!(
+            "Dictionary training needs at least {} representative samples, found {}",
+            MIN_TRAINING_SAMPLES,
+            paths.len()
+        )));
+    }
+
+    let samples = paths
+        .iter()
+        .map(std::fs::read)
+        .collect::<io::Result<Vec<_>>>()?;
+    let dict = zstd::dict::from_samples(&samples, max_size)?;
+    std::fs::write(dict_out, &dict)?;
+
+    info!(
+        "Trained a {} byte zstd dictionary from {} samples, written to {}.",
+        dict.len(),
+        samples.len(),
+        dict_out.display()
+    );
+    Ok(())
+}
+
+/// An uncompressed byte range such as `1000:2000`, used with `--region` to pull a slice out of
+/// a BGZF file via its virtual-offset index.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: u64,
+    end: u64,
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once(':')
+            .ok_or_else(|| Error::msg("Region must be formatted as start:end"))?;
+        Ok(Region {
+            start: start.parse()?,
+            end: end.parse()?,
+        })
+    }
+}
+
+/// Infer a format from a path's extension, e.g. `foo.zst` -> `Format::Zstd`.
+fn format_from_extension(path: &PathBuf) -> Option<Format> {
+    match path.extension()?.to_str()? {
+        "gz" => Some(Format::Gzip),
+        "zst" => Some(Format::Zstd),
+        "bz2" => Some(Format::Bzip2),
+        "sz" => Some(Format::Snap),
+        _ => None,
+    }
+}
+
+/// The file extension crabz appends on compression / strips on decompression for a format.
+fn format_extension(format: Format) -> &'static str {
+    match format {
+        Format::Gzip | Format::Bgzf => "gz",
+        Format::Zstd => "zst",
+        Format::Bzip2 => "bz2",
+        Format::Snap => "sz",
+    }
+}
+
+/// Work out the gzip-style output path for one input file: `foo` -> `foo.gz` on compress, and
+/// `foo.gz` -> `foo` on decompress (or `foo.out` if it doesn't have the expected suffix).
+fn default_output_path(input: &PathBuf, format: Format, decompress: bool) -> PathBuf {
+    let ext = format_extension(format);
+    if decompress {
+        // Compare against `ext` directly rather than going through `format_from_extension`:
+        // that maps `"gz"` to `Format::Gzip`, so it would never match `Format::Bgzf`, which
+        // shares the same `.gz` extension and must strip it the same way.
+        if input.extension().and_then(|e| e.to_str()) == Some(ext) {
+            input.with_extension("")
+        } else {
+            PathBuf::from(format!("{}.out", input.display()))
+        }
+    } else {
+        PathBuf::from(format!("{}.{}", input.display(), ext))
+    }
+}
+
+/// Work out whether we're compressing or decompressing, and with which format, honoring any
+/// explicit `--decompress`/`--format` flags and otherwise falling back to sniffing file
+/// extensions. Stdin can't be rewound to sniff, so extensions are only consulted for real
+/// files.
+fn resolve_mode(opts: &Opts, file: Option<&PathBuf>, output: Option<&PathBuf>) -> (bool, Format) {
+    if let Some(format) = opts.format {
+        return (opts.decompress, format);
+    }
+
+    let is_stdin = file.map_or(true, |path| path.as_os_str() == "-");
+    if is_stdin {
+        return (opts.decompress, Format::Gzip);
+    }
+
+    let input_format = file.and_then(format_from_extension);
+    let output_format = output.and_then(format_from_extension);
+
+    if opts.decompress {
+        return (true, input_format.or(output_format).unwrap_or(Format::Gzip));
+    }
+
+    match (input_format, output_format) {
+        (Some(format), None) => (true, format),
+        (_, Some(format)) => (false, format),
+        _ => (false, Format::Gzip),
+    }
+}
+
+/// Compress or decompress a single file using gzip-style in-place semantics: write to a
+/// derived output path, remove the source on success unless `--keep`, and refuse to clobber an
+/// existing output unless `--force`.
+fn process_file(
+    input_path: &PathBuf,
+    decompress: bool,
+    format: Format,
+    compression_level: u32,
+    num_threads: usize,
+    index_path: Option<&PathBuf>,
+    dict_path: Option<&PathBuf>,
+    keep: bool,
+    force: bool,
+) -> Result<()> {
+    let output_path = default_output_path(input_path, format, decompress);
+    if !force && output_path.exists() {
+        return Err(Error::msg(format!(
+            "{} already exists, use --force to overwrite",
+            output_path.display()
+        )));
+    }
+
+    let input = get_input(Some(input_path.clone()))?;
+    let output = get_output(Some(output_path))?;
+
+    if decompress {
+        run_decompress(input, output, format, num_threads, dict_path)?;
+    } else {
+        run_compress(
+            input,
+            output,
+            compression_level,
+            num_threads,
+            format,
+            index_path,
+            dict_path,
+        )?;
+    }
+
+    if !keep {
+        std::fs::remove_file(input_path)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -85,8 +363,94 @@ fn main() -> Result<()> {
         return Err(Error::msg("Invalid compression level"));
     }
 
-    if opts.decompress {
-        if let Err(err) = run_decompress(get_input(opts.file)?, get_output(opts.output)?) {
+    if let Some(pattern) = &opts.train {
+        let dict_out = opts
+            .dict_out
+            .as_ref()
+            .ok_or_else(|| Error::msg("--train requires --dict-out"))?;
+        return run_train(pattern, dict_out, opts.dict_max_size);
+    }
+
+    let num_threads = opts.compression_threads.unwrap_or_else(num_cpus::get);
+
+    if opts.file.len() > 1 {
+        if opts.output.is_some() {
+            return Err(Error::msg("--output cannot be used with multiple input files"));
+        }
+        if opts.region.is_some() {
+            return Err(Error::msg("--region cannot be used with multiple input files"));
+        }
+        if opts.index.is_some() {
+            // `--index` names a single path; honoring it here would have every file in the
+            // batch overwrite the same index instead of each getting its own.
+            return Err(Error::msg("--index cannot be used with multiple input files"));
+        }
+
+        for file in &opts.file {
+            let (decompress, format) = resolve_mode(&opts, Some(file), None);
+            if let Err(err) = process_file(
+                file,
+                decompress,
+                format,
+                opts.compression_level,
+                num_threads,
+                opts.index.as_ref(),
+                opts.dict.as_ref(),
+                opts.keep,
+                opts.force,
+            ) {
+                error!("{}: {}", file.display(), err);
+                if opts.stop_on_error {
+                    return Err(err);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let file = opts.file.first().cloned();
+
+    // A single real file (not stdin) with no explicit `--output` and no `--region` gets the
+    // same gzip-style in-place handling as the multi-file case above: a derived output path,
+    // source removal, and `--keep`/`--force` semantics.
+    let is_real_file = file.as_ref().map_or(false, |path| path.as_os_str() != "-");
+    if is_real_file && opts.output.is_none() && opts.region.is_none() {
+        let file = file.expect("is_real_file implies file is Some");
+        let (decompress, format) = resolve_mode(&opts, Some(&file), None);
+        return process_file(
+            &file,
+            decompress,
+            format,
+            opts.compression_level,
+            num_threads,
+            opts.index.as_ref(),
+            opts.dict.as_ref(),
+            opts.keep,
+            opts.force,
+        );
+    }
+
+    let (decompress, format) = resolve_mode(&opts, file.as_ref(), opts.output.as_ref());
+
+    if decompress {
+        if let Some(region) = opts.region {
+            let input_path = file
+                .as_ref()
+                .filter(|path| path.as_os_str() != "-")
+                .ok_or_else(|| Error::msg("--region requires a file input, not stdin"))?;
+            let index_path = opts
+                .index
+                .as_ref()
+                .ok_or_else(|| Error::msg("--region requires --index"))?;
+            return run_decompress_region(input_path, index_path, region, get_output(opts.output)?);
+        }
+        if let Err(err) = run_decompress(
+            get_input(file)?,
+            get_output(opts.output)?,
+            format,
+            num_threads,
+            opts.dict.as_ref(),
+        ) {
             if is_broken_pipe(&err) {
                 exit(0)
             }
@@ -94,10 +458,13 @@ fn main() -> Result<()> {
         }
     } else {
         if let Err(err) = run_compress(
-            get_input(opts.file)?,
+            get_input(file)?,
             get_output(opts.output)?,
             opts.compression_level,
-            opts.compression_threads.unwrap_or_else(num_cpus::get),
+            num_threads,
+            format,
+            opts.index.as_ref(),
+            opts.dict.as_ref(),
         ) {
             if is_broken_pipe(&err) {
                 exit(0)
@@ -111,38 +478,316 @@ fn main() -> Result<()> {
 /// Run the compression program, returning any found errors
 fn run_compress<R, W>(
     mut input: R,
-    output: W,
+    mut output: W,
     compression_level: u32,
     num_threads: usize,
+    format: Format,
+    index_path: Option<&PathBuf>,
+    dict_path: Option<&PathBuf>,
 ) -> Result<()>
 where
     R: Read,
     W: Write + Send + 'static,
 {
     info!(
-        "Compressing with {} threads at compression level {}.",
-        num_threads, compression_level
+        "Compressing with {} threads at compression level {} using {:?}.",
+        num_threads, compression_level, format
     );
-    let mut writer = ZBuilder::<Gzip, _>::new()
+    match format {
+        Format::Gzip => {
+            let mut writer = ZBuilder::<Gzip, _>::new()
+                .num_threads(num_threads)
+                .compression_level(Compression::new(compression_level))
+                .from_writer(output);
+            io::copy(&mut input, &mut writer)?;
+            // `finish()` hands back the inner `output`, which is buffered (`BufWriter`) and
+            // would otherwise only flush on drop, silently swallowing a final write error.
+            writer.finish()?.flush()?;
+        }
+        Format::Bgzf => {
+            if let Some(index_path) = index_path {
+                // The index is built by re-scanning the compressed block boundaries, so buffer
+                // the output in memory rather than streaming it straight to `output`.
+                let mut writer = ZBuilder::<Bgzf, _>::new()
+                    .num_threads(num_threads)
+                    .compression_level(Compression::new(compression_level))
+                    .from_writer(Vec::new());
+                io::copy(&mut input, &mut writer)?;
+                let buf = writer.finish()?;
+                write_bgzf_index(&buf, index_path)?;
+                output.write_all(&buf)?;
+                output.flush()?;
+            } else {
+                let mut writer = ZBuilder::<Bgzf, _>::new()
+                    .num_threads(num_threads)
+                    .compression_level(Compression::new(compression_level))
+                    .from_writer(output);
+                io::copy(&mut input, &mut writer)?;
+                writer.finish()?.flush()?;
+            }
+        }
+        Format::Snap => {
+            let mut writer = ZBuilder::<Snap, _>::new()
+                .num_threads(num_threads)
+                .compression_level(Compression::new(compression_level))
+                .from_writer(output);
+            io::copy(&mut input, &mut writer)?;
+            writer.finish()?.flush()?;
+        }
+        Format::Zstd => {
+            // Not `.auto_finish()`: that finishes on drop and discards any error from the final
+            // write, which is exactly the silent-corruption failure mode we need to avoid.
+            let mut writer = match dict_path {
+                Some(dict_path) => zstd::stream::Encoder::with_dictionary(
+                    output,
+                    compression_level as i32,
+                    &std::fs::read(dict_path)?,
+                )?,
+                None => zstd::stream::Encoder::new(output, compression_level as i32)?,
+            };
+            io::copy(&mut input, &mut writer)?;
+            writer.finish()?.flush()?;
+        }
+        Format::Bzip2 => {
+            // Unlike gzip/zstd/snap, bzip2 doesn't accept a level of 0; `main` only checks the
+            // upper bound, so reject an out-of-range level here rather than handing it to the
+            // codec.
+            if !(1..=9).contains(&compression_level) {
+                return Err(Error::msg(
+                    "Bzip2 requires a compression level between 1 and 9",
+                ));
+            }
+            let mut writer = BzEncoder::new(output, bzip2::Compression::new(compression_level));
+            io::copy(&mut input, &mut writer)?;
+            writer.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The byte range of one independent gzip member within a buffered block-gzip input, and how
+/// many bytes it inflates to.
+struct Block {
+    start: usize,
+    end: usize,
+    uncompressed_len: u64,
+}
+
+/// A `Write` sink that only counts the bytes it's given, used to measure an inflated member's
+/// size without buffering it.
+struct CountingSink(u64);
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Scan `data` for gzip member boundaries, returning the byte range and inflated size of each
+/// member.
+///
+/// Every gzip member re-declares the `\x1f\x8b` magic at its own header, so BGZF, mgzip, and
+/// plain `cat a.gz b.gz` concatenations all look the same here: a sequence of independently
+/// inflatable members. There's no way to know a member's compressed length up front, so each
+/// one is actually inflated (and discarded) just to find where it ends.
+///
+/// This has to use `bufread::GzDecoder` directly over the remaining slice rather than
+/// `read::GzDecoder`, which wraps its input in its own `BufReader` and therefore reads well
+/// past the end of the current member looking for more data to buffer. With the `bufread`
+/// decoder, consumption is measured by how much of the slice it actually handed back via
+/// `into_inner`, which lines up exactly with the member's compressed length.
+fn scan_blocks(data: &[u8]) -> Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        let mut sink = CountingSink(0);
+        let mut decoder = BufGzDecoder::new(remaining);
+        io::copy(&mut decoder, &mut sink)?;
+        let after = decoder.into_inner();
+        let consumed = remaining.len() - after.len();
+        if consumed == 0 {
+            break;
+        }
+        blocks.push(Block {
+            start: pos,
+            end: pos + consumed,
+            uncompressed_len: sink.0,
+        });
+        pos += consumed;
+        remaining = after;
+    }
+    Ok(blocks)
+}
+
+/// Write a BGZF virtual-offset index: one `(compressed block offset, cumulative uncompressed
+/// offset)` pair per block, letting a reader seek straight to the block covering a given
+/// uncompressed byte without scanning the whole file.
+fn write_bgzf_index(data: &[u8], path: &PathBuf) -> Result<()> {
+    let blocks = scan_blocks(data)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut uncompressed_offset = 0u64;
+    for block in &blocks {
+        writeln!(writer, "{}\t{}", block.start, uncompressed_offset)?;
+        uncompressed_offset += block.uncompressed_len;
+    }
+    Ok(())
+}
+
+/// Read a BGZF virtual-offset index back into `(compressed offset, uncompressed offset)` pairs.
+fn read_bgzf_index(path: &PathBuf) -> Result<Vec<(u64, u64)>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            let (compressed, uncompressed) = line
+                .split_once('\t')
+                .ok_or_else(|| Error::msg("Malformed BGZF index line"))?;
+            Ok((compressed.parse()?, uncompressed.parse()?))
+        })
+        .collect()
+}
+
+/// Decompress only the BGZF blocks covering `region`'s uncompressed byte range, using a
+/// previously written virtual-offset index to avoid scanning the whole file.
+fn run_decompress_region<W>(
+    input_path: &PathBuf,
+    index_path: &PathBuf,
+    region: Region,
+    mut output: W,
+) -> Result<()>
+where
+    W: Write,
+{
+    let entries = read_bgzf_index(index_path)?;
+    let data = std::fs::read(input_path)?;
+
+    for (i, &(compressed_start, uncompressed_start)) in entries.iter().enumerate() {
+        let next = entries.get(i + 1);
+        let uncompressed_end = next.map_or(u64::MAX, |&(_, next_uncompressed)| next_uncompressed);
+        if uncompressed_end <= region.start || uncompressed_start >= region.end {
+            continue;
+        }
+
+        let compressed_end =
+            next.map_or(data.len(), |&(next_compressed, _)| next_compressed as usize);
+        let mut block = Vec::new();
+        GzDecoder::new(&data[compressed_start as usize..compressed_end]).read_to_end(&mut block)?;
+
+        let block_len = block.len() as u64;
+        let block_start = region.start.saturating_sub(uncompressed_start).min(block_len) as usize;
+        let block_end = region.end.saturating_sub(uncompressed_start).min(block_len) as usize;
+        if block_start < block_end {
+            output.write_all(&block[block_start..block_end])?;
+        }
+    }
+    Ok(())
+}
+
+/// Inflate blocks across `num_threads` workers, writing each batch out to `output` in order as
+/// soon as it's ready rather than holding the whole decompressed file in memory at once.
+fn decompress_blocks_parallel<W>(
+    data: &[u8],
+    blocks: &[Block],
+    num_threads: usize,
+    output: &mut W,
+) -> Result<()>
+where
+    W: Write,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(num_threads)
-        .compression_level(Compression::new(compression_level))
-        .from_writer(output);
-    io::copy(&mut input, &mut writer)?;
-    writer.finish()?;
+        .build()?;
+    for batch in blocks.chunks(num_threads.max(1)) {
+        let inflated: Vec<Vec<u8>> = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|block| -> Result<Vec<u8>> {
+                    let mut buf = Vec::new();
+                    GzDecoder::new(&data[block.start..block.end]).read_to_end(&mut buf)?;
+                    Ok(buf)
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for buf in inflated {
+            output.write_all(&buf)?;
+        }
+    }
     Ok(())
 }
 
 /// Run the compression program, returning any found errors
-fn run_decompress<R, W>(mut input: R, output: W) -> Result<()>
+fn run_decompress<R, W>(
+    mut input: R,
+    mut output: W,
+    format: Format,
+    num_threads: usize,
+    dict_path: Option<&PathBuf>,
+) -> Result<()>
 where
     R: Read,
     W: Write + Send + 'static,
 {
-    info!("Decompressing.");
+    info!("Decompressing {:?}.", format);
 
-    let mut writer = GzDecoder::new(output);
-    io::copy(&mut input, &mut writer)?;
-    writer.finish()?;
+    // `output` is always a buffered writer (see `get_output`/`process_file`), so every arm
+    // below flushes it explicitly and propagates the result instead of relying on `Drop`, which
+    // would silently discard a failure on the final buffered write.
+    match format {
+        Format::Gzip => {
+            // `MultiGzDecoder` transparently walks every member in the stream until it hits
+            // true EOF, validating each member's CRC32 and ISIZE trailer as it goes. This
+            // decodes `cat a.gz b.gz > c.gz`-style concatenations correctly while still
+            // streaming straight off `input`, so a large plain gzip never has to be buffered.
+            let mut reader = MultiGzDecoder::new(input);
+            io::copy(&mut reader, &mut output)?;
+            output.flush()?;
+        }
+        Format::Bgzf => {
+            // BGZF is a sequence of independent gzip members, so the blocks can be inflated in
+            // parallel. That requires knowing the block boundaries up front, so (unlike plain
+            // gzip above) the input has to be buffered in full before decoding starts.
+            let mut buf = Vec::new();
+            input.read_to_end(&mut buf)?;
+            let blocks = scan_blocks(&buf)?;
+            if blocks.len() > 1 {
+                info!(
+                    "Decompressing {} blocks with {} threads.",
+                    blocks.len(),
+                    num_threads
+                );
+                decompress_blocks_parallel(&buf, &blocks, num_threads, &mut output)?;
+            } else {
+                let mut reader = MultiGzDecoder::new(&buf[..]);
+                io::copy(&mut reader, &mut output)?;
+            }
+            output.flush()?;
+        }
+        Format::Bzip2 => {
+            let mut writer = BzDecoder::new(output);
+            io::copy(&mut input, &mut writer)?;
+            writer.finish()?.flush()?;
+        }
+        Format::Snap => {
+            let mut reader = snap::read::FrameDecoder::new(input);
+            io::copy(&mut reader, &mut output)?;
+            output.flush()?;
+        }
+        Format::Zstd => {
+            let mut reader = match dict_path {
+                Some(dict_path) => {
+                    zstd::stream::Decoder::with_dictionary(input, &std::fs::read(dict_path)?)?
+                }
+                None => zstd::stream::Decoder::new(input)?,
+            };
+            io::copy(&mut reader, &mut output)?;
+            output.flush()?;
+        }
+    }
     Ok(())
 }
 /// Parse args and set up logging / tracing
@@ -154,3 +799,141 @@ fn setup() -> Opts {
 
     Opts::from_args()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A unique path under the system temp dir for a test artifact, so parallel test threads
+    /// don't clobber each other's files.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "crabz-test-{}-{:?}-{}",
+            std::process::id(),
+            std::thread::current().id(),
+            name
+        ))
+    }
+
+    fn roundtrip(format: Format) {
+        let original = b"the quick brown fox jumps over the lazy dog\n".repeat(200);
+
+        let compressed_path = temp_path(&format!("{:?}.compressed", format));
+        run_compress(
+            &original[..],
+            File::create(&compressed_path).unwrap(),
+            3,
+            1,
+            format,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let decompressed_path = temp_path(&format!("{:?}.decompressed", format));
+        run_decompress(
+            File::open(&compressed_path).unwrap(),
+            File::create(&decompressed_path).unwrap(),
+            format,
+            1,
+            None,
+        )
+        .unwrap();
+
+        let decompressed = std::fs::read(&decompressed_path).unwrap();
+        std::fs::remove_file(&compressed_path).ok();
+        std::fs::remove_file(&decompressed_path).ok();
+
+        assert_eq!(decompressed, original, "{:?} round-trip mismatch", format);
+    }
+
+    #[test]
+    fn gzip_roundtrip() {
+        roundtrip(Format::Gzip);
+    }
+
+    #[test]
+    fn bgzf_roundtrip() {
+        roundtrip(Format::Bgzf);
+    }
+
+    #[test]
+    fn zstd_roundtrip() {
+        roundtrip(Format::Zstd);
+    }
+
+    #[test]
+    fn bzip2_roundtrip() {
+        roundtrip(Format::Bzip2);
+    }
+
+    #[test]
+    fn snap_roundtrip() {
+        roundtrip(Format::Snap);
+    }
+
+    #[test]
+    fn multi_member_gzip_decodes_as_one_stream() {
+        let mut encode_member = |payload: &[u8]| -> Vec<u8> {
+            let mut member = Vec::new();
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut member, flate2::Compression::new(3));
+            encoder.write_all(payload).unwrap();
+            encoder.finish().unwrap();
+            member
+        };
+
+        let mut concatenated = encode_member(b"first member");
+        concatenated.extend(encode_member(b"second member"));
+
+        let decompressed_path = temp_path("multi-member.decompressed");
+        run_decompress(
+            &concatenated[..],
+            File::create(&decompressed_path).unwrap(),
+            Format::Gzip,
+            1,
+            None,
+        )
+        .unwrap();
+
+        let decompressed = std::fs::read(&decompressed_path).unwrap();
+        std::fs::remove_file(&decompressed_path).ok();
+
+        assert_eq!(decompressed, b"first membersecond member");
+    }
+
+    #[test]
+    fn bgzf_index_and_region_roundtrip() {
+        // Big enough, and varied enough, that a block-boundary or offset bug would extract the
+        // wrong bytes rather than happening to match by coincidence.
+        let original: Vec<u8> = (0..2_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        let compressed_path = temp_path("bgzf-region.compressed");
+        let index_path = temp_path("bgzf-region.idx");
+        run_compress(
+            &original[..],
+            File::create(&compressed_path).unwrap(),
+            3,
+            4,
+            Format::Bgzf,
+            Some(&index_path),
+            None,
+        )
+        .unwrap();
+
+        let region = Region {
+            start: 500_000,
+            end: 1_500_000,
+        };
+        let mut extracted = Vec::new();
+        run_decompress_region(&compressed_path, &index_path, region, &mut extracted).unwrap();
+
+        std::fs::remove_file(&compressed_path).ok();
+        std::fs::remove_file(&index_path).ok();
+
+        assert_eq!(
+            extracted,
+            original[region.start as usize..region.end as usize]
+        );
+    }
+}